@@ -1,59 +1,144 @@
-struct StyleSheetBuf {
-    source_ptr: *mut String,
+mod owned;
 
-    // Why use an option here? See:
-    // https://doc.rust-lang.org/nomicon/destructors.html
-    sheet: Option<StyleSheet<'static>>,
-}
+use owned::{Owned, Yokeable};
+
+// `StyleSheetBuf` used to hand-roll the `Box::into_raw`/`Box::from_raw` self-referential dance
+// itself; now it's just `owned::Owned<String, StyleSheet<'static>>` under a newtype, so the
+// dance lives in one place (`owned.rs`) instead of being duplicated here.
+struct StyleSheetBuf(Owned<String, StyleSheet<'static>>);
 
 impl StyleSheetBuf {
-    // "Safely" (?) creates a new `StyleSheetBuf`. To do so, we require an owned string type which
-    // will then be "leaked" after moving it to the heap. This is safe because since we own it it
-    // should never be dropped without our consent.
-    //
-    // We then get a raw pointer and use it to get a static reference, which is used to create a
-    // "normal" style sheet. Such reference is, of course, static. This means that it may live up
-    // until the end of the program.
+    // Parses a `StyleSheet` that borrows from `source` and keeps both together via `Owned` (see
+    // `owned.rs`). `String` is `StableDeref`, so its address is stable even though `source` itself
+    // gets moved into `self` right after.
     pub fn new(source: String) -> StyleSheetBuf {
-        let boxed_source = Box::new(source);
-        let source_ptr = Box::into_raw(boxed_source);
-
-        // SAFETY: The deref is safe since we just obtained the pointer by using `into_raw`,
-        // which must return a "safe" pointer that fulfills all the deref requirements.
-        let str_ref: &'static str = unsafe { (*source_ptr).as_str() };
-
-        let sheet = StyleSheet::parse(str_ref);
-        StyleSheetBuf {
-            source_ptr,
-            sheet: Some(sheet),
+        match Self::try_new(source) {
+            Ok(buf) => buf,
+            Err((err, _)) => panic!("failed to parse style sheet: {err:?}"),
         }
     }
 
+    // Same as `new`, but doesn't leak `source` if parsing panics or fails: on failure the caller
+    // gets the `String` back, and on a panic it is dropped during unwinding like any other local.
+    pub fn try_new(source: String) -> Result<StyleSheetBuf, (ParseError, String)> {
+        Owned::try_new(source, |s| StyleSheet::try_parse(s)).map(StyleSheetBuf)
+    }
+
     // Self explanatory.
     pub fn sheet(&self) -> &StyleSheet<'static> {
-        // SAFETY: `self.sheet` only is `None` within `StyleSheetBuf`'s destructor.
-        unsafe { self.sheet.as_ref().unwrap_unchecked() }
+        // SAFETY: `Owned::get` already ties the borrow to `&self`; widening the `StyleSheet<'_>`
+        // it returns back to `StyleSheet<'static>` is the same "honest by convention, not by the
+        // type system" contract the rest of this type relies on (see `Deref`, `into_foreign`) —
+        // needed because `Deref::Target` has to name a single, lifetime-free type.
+        unsafe { std::mem::transmute(self.0.get()) }
+    }
+
+    // Recovers the original `String`, running the dependent's destructor up front instead of
+    // going through `Drop`.
+    #[allow(dead_code)]
+    pub fn into_source(self) -> String {
+        // SAFETY: `StyleSheetBuf` has a `Drop` impl, so we can't partially move `self.0` out of
+        // it normally; read it out manually and forget `self` right after so neither its `Drop`
+        // nor the drop glue for the original `self.0` runs (which would free the source twice).
+        let owned = unsafe { std::ptr::read(&self.0) };
+        std::mem::forget(self);
+        owned.into_owner()
+    }
+
+    // Hands ownership of this `StyleSheetBuf` to a foreign (e.g. C or WASM) caller as an opaque
+    // pointer. The caller must eventually pass it to exactly one `from_foreign` call to avoid
+    // leaking it, and may call `borrow` any number of times in between.
+    #[allow(dead_code)]
+    pub fn into_foreign(self) -> *const std::ffi::c_void {
+        Box::into_raw(Box::new(self)) as *const std::ffi::c_void
+    }
+
+    // Reclaims a `StyleSheetBuf` previously given away via `into_foreign`, dropping the `sheet`
+    // and then the source string in the correct order on the Rust side.
+    //
+    // # Safety
+    // `ptr` must have come from `into_foreign`, and this must be the only `from_foreign` call for
+    // it (each `into_foreign` pointer may be reclaimed exactly once).
+    #[allow(dead_code)]
+    pub unsafe fn from_foreign(ptr: *const std::ffi::c_void) -> StyleSheetBuf {
+        unsafe { *Box::from_raw(ptr as *mut StyleSheetBuf) }
+    }
+
+    // Borrows the `StyleSheet` behind a pointer handed out by `into_foreign`, without taking
+    // ownership back.
+    //
+    // # Safety
+    // `ptr` must have come from `into_foreign` and must not yet have been passed to
+    // `from_foreign`. The returned reference must not outlive that constraint.
+    #[allow(dead_code)]
+    pub unsafe fn borrow<'a>(ptr: *const std::ffi::c_void) -> &'a StyleSheet<'a> {
+        let buf: &'a StyleSheetBuf = unsafe { &*(ptr as *const StyleSheetBuf) };
+        buf.sheet()
+    }
+
+    // Layers a second dependent on top of this one, e.g. a `Vec<Rule<'s>>` borrowing from
+    // `self.sheet()`'s tokens. The result stores `self` (boxed, so the sheet `f` borrowed keeps
+    // its address) alongside the new dependent and, via
+    // `StyleSheetBuf2`'s field order, guarantees the new dependent is dropped before `self`
+    // (and therefore before the sheet and the source string it in turn depends on) no matter
+    // how deep the chain gets.
+    #[allow(dead_code)]
+    pub fn attach<D2: for<'a> Yokeable<'a>>(
+        self,
+        f: impl for<'a> FnOnce(&'a StyleSheet<'a>) -> <D2 as Yokeable<'a>>::Output,
+    ) -> StyleSheetBuf2<D2> {
+        let inner = Box::new(self);
+        let dependent = f(inner.sheet());
+
+        // SAFETY: `inner.sheet()` borrows the sheet inside the box and, through it, the source
+        // string; moving the `Box` into `StyleSheetBuf2` doesn't move either, and `inner` keeps
+        // both alive for at least as long as `dependent` (see its field order and `get`, which
+        // mirrors `Owned::get`/`Owned::new` in `owned.rs`).
+        let dependent = unsafe {
+            std::mem::transmute::<<D2 as Yokeable<'_>>::Output, <D2 as Yokeable<'static>>::Output>(
+                dependent,
+            )
+        };
+
+        StyleSheetBuf2 { dependent, inner }
     }
 }
 
-// "Safely" (?) drop it.
-impl Drop for StyleSheetBuf {
-    fn drop(&mut self) {
-        // Ensures the underlying `StyleSheet` is dropped before the string is dropped. This is
-        // needed to avoid an use after free in the case of `StyleSheet` defining its own destructor
-        // which could then use the string we need to drop next.
-        drop(self.sheet.take());
-        // The `self.sheet` field is now `None`, which means that `StyleSheet`s destructor won't be
-        // executed again. It is now safe to clean up the string.
+// A second, caller-chosen dependent layered on top of a `StyleSheetBuf`, forming a 3-level
+// resource stack: `source` (`String`) -> `sheet` (`StyleSheet`) -> `dependent` (`D2`).
+//
+// `dependent` is declared before `inner` so the default field-drop glue tears it down first,
+// while the sheet and source it may borrow from are still alive inside `inner`.
+#[allow(dead_code)]
+struct StyleSheetBuf2<D2: for<'a> Yokeable<'a>> {
+    dependent: <D2 as Yokeable<'static>>::Output,
+    inner: Box<StyleSheetBuf>,
+}
 
-        println!("  Will drop StyleSheetBuf...");
+impl<D2: for<'a> Yokeable<'a>> StyleSheetBuf2<D2> {
+    #[allow(dead_code)]
+    pub fn get<'a>(&'a self) -> &'a <D2 as Yokeable<'a>>::Output {
+        // SAFETY: same reasoning as `Owned::get` in `owned.rs`: this only shortens the borrow's
+        // lifetime back down to `&self`.
+        unsafe { std::mem::transmute(&self.dependent) }
+    }
+}
 
-        // SAFETY: It is safe because there is no way to drop such pointer before (i.e. a double
-        // free can't happen) and the pointer wasn't modified since its creation by `into_raw`.
-        unsafe {
-            drop(Box::from_raw(self.source_ptr));
-        }
+impl<D2: for<'a> Yokeable<'a>> std::ops::Deref for StyleSheetBuf2<D2> {
+    type Target = StyleSheetBuf;
 
+    fn deref(&self) -> &StyleSheetBuf {
+        &self.inner
+    }
+}
+
+// Logs the start of the drop for the demo in `main`; both lines print before the inner `Owned`
+// is dropped, so `StyleSheet`'s own drop messages follow them. The actual dependent-before-owner
+// ordering is now `Owned`'s job (see its field order in `owned.rs`), not something
+// `StyleSheetBuf` has to enforce by hand.
+impl Drop for StyleSheetBuf {
+    fn drop(&mut self) {
+        println!("  Will drop StyleSheetBuf...");
         println!("    Done.");
     }
 }
@@ -71,14 +156,32 @@ struct StyleSheet<'s> {
     parsed: &'s str,
 }
 
+#[derive(Debug)]
+struct ParseError(#[allow(dead_code)] &'static str);
+
 impl<'s> StyleSheet<'s> {
+    #[allow(dead_code)]
     pub fn parse(source: &'s str) -> Self {
-        StyleSheet {
+        // This demo parser never actually fails; real ones do, which is what `try_parse` is for.
+        Self::try_parse(source).unwrap()
+    }
+
+    pub fn try_parse(source: &'s str) -> Result<Self, ParseError> {
+        Ok(StyleSheet {
             parsed: source.trim(),
-        }
+        })
     }
 }
 
+// Lets `StyleSheet<'static>` be used as the dependent of `owned::Owned`, which is what
+// `StyleSheetBuf` above is built on.
+//
+// SAFETY: `StyleSheet<'a>` for varying `'a` is the same shape (one covariant borrow field), so
+// shortening `'static` to any `'a` is a sound subtype relationship, as `Yokeable` requires.
+unsafe impl<'a> Yokeable<'a> for StyleSheet<'static> {
+    type Output = StyleSheet<'a>;
+}
+
 impl Drop for StyleSheet<'_> {
     fn drop(&mut self) {
         println!("  Will drop StyleSheet:");
@@ -106,3 +209,62 @@ fn parse_file(path: &str) -> StyleSheetBuf {
     let source = std::fs::read_to_string(path).unwrap();
     StyleSheetBuf::new(source)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_source_round_trip() {
+        let buf = StyleSheetBuf::new(" hello ".to_string());
+        assert_eq!(buf.into_source(), " hello ");
+    }
+
+    #[test]
+    fn ffi_round_trip() {
+        let buf = StyleSheetBuf::new(" hi ".to_string());
+        let ptr = buf.into_foreign();
+
+        // SAFETY: `ptr` just came from `into_foreign` and hasn't been passed to `from_foreign`.
+        let borrowed = unsafe { StyleSheetBuf::borrow(ptr) };
+        assert_eq!(borrowed.parsed, "hi");
+
+        // SAFETY: `ptr` came from `into_foreign` and this is its only `from_foreign` call.
+        let reclaimed = unsafe { StyleSheetBuf::from_foreign(ptr) };
+        assert_eq!(reclaimed.sheet().parsed, "hi");
+    }
+
+    // A third-level dependent that reads through its borrow on drop, same as `StyleSheet` does.
+    // If `attach` ever dropped it after the sheet/source instead of before, this read would see
+    // freed memory instead of the expected text.
+    struct Echo<'s> {
+        parsed: &'s str,
+        log: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    }
+
+    unsafe impl<'a> Yokeable<'a> for Echo<'static> {
+        type Output = Echo<'a>;
+    }
+
+    impl Drop for Echo<'_> {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push(format!("dependent saw {:?}", self.parsed));
+        }
+    }
+
+    #[test]
+    fn attach_drops_top_down() {
+        let log = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let buf = StyleSheetBuf::new(" hi ".to_string());
+        let stacked = buf.attach::<Echo<'static>>({
+            let log = std::rc::Rc::clone(&log);
+            move |sheet| Echo {
+                parsed: sheet.parsed,
+                log,
+            }
+        });
+
+        drop(stacked);
+        assert_eq!(*log.borrow(), vec![r#"dependent saw "hi""#.to_string()]);
+    }
+}
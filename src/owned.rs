@@ -0,0 +1,255 @@
+//! A reusable, generic version of the self-referential "owned, then parsed" pattern
+//! demonstrated by `StyleSheetBuf` in `main.rs`.
+//!
+//! `StyleSheetBuf` hardcodes `String` as the owner and `StyleSheet<'static>` as the thing that
+//! borrows from it. `Owned<O, D>` generalizes that: any `O: StableDeref` can be the owner (its
+//! target is guaranteed to stay at the same address even if `O` itself moves, so we can store it
+//! by value instead of leaking a `Box` and keeping a raw pointer around), and any `D` that knows
+//! how to describe itself at other lifetimes via `Yokeable` can be the dependent.
+//!
+//! `StyleSheetBuf` in `main.rs` is itself just a newtype over `Owned<String, StyleSheet<'static>>` now;
+//! `LenientOwned` isn't used outside tests yet, hence the blanket `dead_code` allowance below.
+
+#![allow(dead_code)]
+
+use stable_deref_trait::StableDeref;
+use std::mem::transmute;
+
+/// Expresses that `Self` is the `'static`-lifetime member of a family of types that all share
+/// the same shape but borrow for a shorter lifetime `'a`.
+///
+/// For example `StyleSheet<'static>: Yokeable<'a, Output = StyleSheet<'a>>` says "a `StyleSheet`
+/// borrowing for `'a` looks like `StyleSheet<'a>`". [`Owned`] uses this to go from "the lifetime
+/// a dependent was actually built with" to "the `'static` lifetime it's stored as" and back,
+/// without the caller ever having to write an unsafe transmute themselves.
+///
+/// # Safety
+/// For a fixed `Self`, every `<Self as Yokeable<'a>>::Output` across all `'a` must be the *same*
+/// type shape, differing only by substituting `'a` for `Self`'s own lifetime parameter in a
+/// covariant position (i.e. `Output` at a shorter `'a` must be a valid subtype of `Output` at a
+/// longer one). `Owned::new`/`Owned::get` (and `StyleSheetBuf::attach`/`StyleSheetBuf2::get`) `transmute`
+/// between different `Output`s on the assumption that this holds; an impl that sets `Output` to
+/// an unrelated type, or to something invariant over `'a`, makes those transmutes unsound.
+pub unsafe trait Yokeable<'a> {
+    type Output: 'a;
+}
+
+/// A self-referential container pairing a stable-address `owner` with a `dependent` that
+/// borrows from it.
+///
+/// `O: StableDeref` is what makes this sound: moving an `Owned` around moves `O`, but never
+/// moves `O::Target`, so a reference handed to the dependent at construction time stays valid
+/// for as long as `owner` is alive. A hand-rolled version would uphold that invariant manually
+/// with `Box::into_raw`/`Box::from_raw`; `Owned` checks it via the `StableDeref` bound instead.
+pub struct Owned<O: StableDeref, D: for<'a> Yokeable<'a>> {
+    // Safety invariant: `dependent` was produced by `f` borrowing from `owner.deref()` and then
+    // had its lifetime transmuted up to `'static` purely for storage. It must never be handed out
+    // at any lifetime longer than the `&self` borrow that produced it (see `get`). `dependent` is
+    // declared before `owner` so the default field-drop glue tears it down first, while `owner`
+    // (and therefore its target) is still alive.
+    dependent: <D as Yokeable<'static>>::Output,
+    owner: O,
+}
+
+impl<O: StableDeref, D: for<'a> Yokeable<'a>> Owned<O, D> {
+    /// Builds an `Owned` by handing `f` a reference into `owner`'s target and keeping whatever
+    /// it returns alongside `owner`.
+    pub fn new(
+        owner: O,
+        f: impl for<'a> FnOnce(&'a O::Target) -> <D as Yokeable<'a>>::Output,
+    ) -> Self {
+        let dependent = f(&owner);
+
+        // SAFETY: `O: StableDeref` guarantees `owner.deref()`'s address doesn't change even
+        // though `owner` is about to be moved into the struct below, so the borrow `f` took is
+        // still valid for as long as `owner` is. We only extend the *type's* lifetime parameter
+        // to `'static` here for storage; `get` transmutes it back down to `&self`'s lifetime, so
+        // callers can never observe it outliving `owner`.
+        let dependent = unsafe {
+            transmute::<<D as Yokeable<'_>>::Output, <D as Yokeable<'static>>::Output>(dependent)
+        };
+
+        Owned { owner, dependent }
+    }
+
+    /// Borrows the dependent, with its lifetime tied to `&self` rather than the `'static` it's
+    /// stored as.
+    pub fn get<'a>(&'a self) -> &'a <D as Yokeable<'a>>::Output {
+        // SAFETY: `<D as Yokeable<'a>>::Output` and `<D as Yokeable<'static>>::Output` are the
+        // same family member shrunk to a shorter lifetime, which is exactly what `Yokeable`
+        // promises; shortening a borrow's lifetime is always sound.
+        unsafe { transmute(&self.dependent) }
+    }
+
+    /// Returns the owner, dropping the dependent first.
+    pub fn into_owner(self) -> O {
+        self.owner
+    }
+
+    /// Like [`new`](Self::new), but for a fallible `f`: on error, hands `owner` back instead of
+    /// leaking it. Unlike the hand-rolled `Box::into_raw` version of this dance, there's nothing
+    /// to reclaim here if `f` panics or returns `Err` — `owner` is still a plain local at that
+    /// point, so it's dropped by ordinary unwinding/control flow like any other value.
+    pub fn try_new<E>(
+        owner: O,
+        f: impl for<'a> FnOnce(&'a O::Target) -> Result<<D as Yokeable<'a>>::Output, E>,
+    ) -> Result<Self, (E, O)> {
+        // Confined to its own block so the borrow `f` takes of `owner` (and the temporary holding
+        // `f`'s result) is gone by the time we need to move `owner` below -- otherwise rustc's
+        // conservative drop scoping for the match's scrutinee temporary would keep `owner`
+        // borrowed until the end of this function.
+        let result: Result<<D as Yokeable<'static>>::Output, E> = {
+            match f(&owner) {
+                // SAFETY: same reasoning as `new`.
+                Ok(dependent) => Ok(unsafe {
+                    transmute::<<D as Yokeable<'_>>::Output, <D as Yokeable<'static>>::Output>(
+                        dependent,
+                    )
+                }),
+                Err(err) => Err(err),
+            }
+        };
+
+        match result {
+            Ok(dependent) => Ok(Owned { owner, dependent }),
+            Err(err) => Err((err, owner)),
+        }
+    }
+}
+
+/// Marker for dependent types whose `Drop` impl (if any) never reads data reached through their
+/// borrowed lifetime.
+///
+/// `Owned<O, D>`'s field order forces `dependent` to drop before `owner` precisely because a
+/// dependent is allowed to read through its borrow while being dropped (the demo `StyleSheet`
+/// does exactly that). Types that opt into this marker give that up, which is what lets
+/// [`LenientOwned`] free `source_ptr` without waiting on `dependent`'s destructor.
+///
+/// # Safety
+/// Implementors must not access, directly or transitively, any data borrowed from the owner
+/// inside their `Drop::drop`. Types with no `Drop` impl at all trivially satisfy this.
+pub unsafe trait DropDoesNotReadBorrow {}
+
+/// Like [`Owned`], but for dependents that have opted into [`DropDoesNotReadBorrow`].
+///
+/// This still goes through the same HRTB-closure-plus-`'static`-erasure dance as `Owned` (there's
+/// no sound way around it: a constructor that let the caller name the borrow's lifetime directly,
+/// e.g. `FnOnce(&'s str) -> D` for an `'s` chosen by the caller, would let them pick `'s =
+/// 'static` and then read the borrow back out after `self` is dropped). What changes is `Drop`:
+/// `dependent` is stored as `<D as Yokeable<'static>>::Output`, which (unlike a genuine `&'s`
+/// field) carries no lifetime for the drop-checker to track in the first place, so `Drop` below
+/// is free to run `source_ptr`'s destructor before `dependent`'s own — no eyepatch needed, since
+/// there's nothing here for one to relax. The only thing standing between that and a use-after-
+/// free is `dependent`'s `Drop` impl (if any) never reading through the now-dangling borrow,
+/// which is exactly what the `DropDoesNotReadBorrow` bound promises.
+pub struct LenientOwned<D: for<'a> Yokeable<'a>>
+where
+    for<'a> <D as Yokeable<'a>>::Output: DropDoesNotReadBorrow,
+{
+    // Field order doesn't matter for drop-check purposes here (see the `Drop` impl below), but
+    // matching `Owned`'s declaration order keeps the two structs easy to compare at a glance.
+    dependent: <D as Yokeable<'static>>::Output,
+    source_ptr: *mut String,
+}
+
+impl<D: for<'a> Yokeable<'a>> LenientOwned<D>
+where
+    for<'a> <D as Yokeable<'a>>::Output: DropDoesNotReadBorrow,
+{
+    /// Builds a `LenientOwned` by handing `f` a reference into a freshly-owned `String` and
+    /// keeping whatever it returns.
+    pub fn new(
+        source: String,
+        f: impl for<'a> FnOnce(&'a str) -> <D as Yokeable<'a>>::Output,
+    ) -> Self {
+        let source_ptr = Box::into_raw(Box::new(source));
+
+        // SAFETY: `source_ptr` was just produced by `into_raw`, so the deref is valid.
+        let str_ref: &str = unsafe { (*source_ptr).as_str() };
+        let dependent = f(str_ref);
+
+        // SAFETY: same reasoning as `Owned::new` -- `f` is universally quantified over the
+        // borrow's lifetime, so it can't smuggle out a caller-chosen lifetime the way a named
+        // `'s` parameter on `new` itself could. Extending the result to `'static` here is only
+        // for storage; `get` transmutes it back down to `&self`'s lifetime.
+        let dependent = unsafe {
+            transmute::<<D as Yokeable<'_>>::Output, <D as Yokeable<'static>>::Output>(dependent)
+        };
+
+        LenientOwned {
+            dependent,
+            source_ptr,
+        }
+    }
+
+    /// Borrows the dependent, with its lifetime tied to `&self` rather than the `'static` it's
+    /// stored as.
+    pub fn get<'a>(&'a self) -> &'a <D as Yokeable<'a>>::Output {
+        // SAFETY: same reasoning as `Owned::get`: this only shortens the borrow's lifetime back
+        // down to `&self`.
+        unsafe { transmute(&self.dependent) }
+    }
+}
+
+impl<D: for<'a> Yokeable<'a>> Drop for LenientOwned<D>
+where
+    for<'a> <D as Yokeable<'a>>::Output: DropDoesNotReadBorrow,
+{
+    fn drop(&mut self) {
+        // SAFETY: `source_ptr` was produced by `into_raw` in `new` and hasn't been freed before.
+        // `dependent`'s own destructor (if any) runs right after this function returns, by which
+        // point `source_ptr`'s backing string is gone; that's sound only because the `for<'a> <D
+        // as Yokeable<'a>>::Output: DropDoesNotReadBorrow` bound on this impl guarantees `dependent`
+        // never reads through that borrow while dropping.
+        unsafe {
+            drop(Box::from_raw(self.source_ptr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dependent with no real borrow, just enough to implement `Yokeable`.
+    struct Len(usize);
+
+    unsafe impl<'a> Yokeable<'a> for Len {
+        type Output = Len;
+    }
+
+    #[test]
+    fn construct_get_and_drop() {
+        let owned = Owned::<String, Len>::new("hello world".to_string(), |s| Len(s.len()));
+        assert_eq!(owned.get().0, 11);
+        drop(owned);
+    }
+
+    #[test]
+    fn try_new_returns_owner_on_err() {
+        let result =
+            Owned::<String, Len>::try_new("hello".to_string(), |_s| Err::<Len, &'static str>("boom"));
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err((err, owner)) => {
+                assert_eq!(err, "boom");
+                assert_eq!(owner, "hello");
+            }
+        }
+    }
+
+    /// A dependent with no `Drop` impl at all, so it trivially satisfies `DropDoesNotReadBorrow`.
+    struct NoDrop(usize);
+
+    unsafe impl DropDoesNotReadBorrow for NoDrop {}
+    unsafe impl<'a> Yokeable<'a> for NoDrop {
+        type Output = NoDrop;
+    }
+
+    #[test]
+    fn lenient_owned_construct_get_and_drop() {
+        let owned = LenientOwned::<NoDrop>::new("hello world".to_string(), |s| NoDrop(s.len()));
+        assert_eq!(owned.get().0, 11);
+        drop(owned);
+    }
+}